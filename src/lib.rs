@@ -1,10 +1,68 @@
 use nih_plug::prelude::*;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+/// How the two delay lines relate to each other.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum StereoMode {
+    #[id = "independent"]
+    #[name = "Independent"]
+    Independent,
+    #[id = "ping_pong"]
+    #[name = "Ping-Pong"]
+    PingPong,
+}
+
+/// Note divisions available when the delay time is synced to the host tempo.
+/// The value of each variant is the length of the division in quarter notes.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+enum NoteDivision {
+    #[id = "quarter"]
+    #[name = "1/4"]
+    Quarter,
+    #[id = "eighth"]
+    #[name = "1/8"]
+    Eighth,
+    #[id = "eighth_dotted"]
+    #[name = "1/8 D"]
+    EighthDotted,
+    #[id = "eighth_triplet"]
+    #[name = "1/8 T"]
+    EighthTriplet,
+    #[id = "sixteenth"]
+    #[name = "1/16"]
+    Sixteenth,
+}
+
+impl NoteDivision {
+    /// Length of the division in quarter notes.
+    fn quarter_notes(self) -> f64 {
+        match self {
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::EighthDotted => 0.75,
+            NoteDivision::EighthTriplet => 1.0 / 3.0,
+            NoteDivision::Sixteenth => 0.25,
+        }
+    }
+}
+
+/// Default maximum delay, in milliseconds, and the top of the `delay_time` range.
+/// The buffers are sized in `initialize` for the larger of this and the persisted
+/// `max_delay_ms` setting, which can't change while playing.
+const DEFAULT_MAX_DELAY_MS: f32 = 5000.0;
 
 struct SlapDelay {
     params: Arc<SlapDelayParams>,
     delay_buffer: Vec<Vec<f32>>,
     write_pos: usize,
+
+    /// Delay length (in samples) the new tap is heading towards.
+    active_delay: f32,
+    /// Delay length (in samples) the old tap is fading out from.
+    old_delay: f32,
+    /// Crossfade coefficient from `old_delay` to `active_delay`; `1.0` when no
+    /// fade is in progress.
+    fade: f32,
 }
 
 #[derive(Params)]
@@ -14,6 +72,29 @@ struct SlapDelayParams {
 
     #[id = "dry_wet"]
     pub dry_wet: FloatParam,
+
+    #[id = "feedback"]
+    pub feedback: FloatParam,
+
+    #[id = "intensity"]
+    pub intensity: FloatParam,
+
+    #[id = "sync"]
+    pub sync: BoolParam,
+
+    #[id = "division"]
+    pub division: EnumParam<NoteDivision>,
+
+    #[id = "stereo_mode"]
+    pub stereo_mode: EnumParam<StereoMode>,
+
+    /// Maximum delay time in milliseconds. State-file only: there is no editor,
+    /// so the generic UI does not surface it; it is read once in `initialize` to
+    /// size the delay buffers. The `delay_time` slider spans up to
+    /// `DEFAULT_MAX_DELAY_MS`, so the buffer is always sized to cover at least
+    /// that range; a larger persisted value only extends the headroom.
+    #[persist = "max_delay_ms"]
+    pub max_delay_ms: Arc<RwLock<f32>>,
 }
 
 impl Default for SlapDelay {
@@ -22,6 +103,9 @@ impl Default for SlapDelay {
             params: Arc::new(SlapDelayParams::default()),
             delay_buffer: vec![Vec::new(); 2], // Stereo buffer
             write_pos: 0,
+            active_delay: -1.0,
+            old_delay: -1.0,
+            fade: 1.0,
         }
     }
 }
@@ -34,7 +118,7 @@ impl Default for SlapDelayParams {
                 120.0,
                 FloatRange::Linear {
                     min: 1.0,
-                    max: 1000.0,
+                    max: DEFAULT_MAX_DELAY_MS,
                 },
             )
             .with_unit(" ms")
@@ -44,10 +128,41 @@ impl Default for SlapDelayParams {
                 .with_unit("")
                 .with_value_to_string(Arc::new(|value| format!("{:.1}", value)))
                 .with_step_size(0.001),
+
+            feedback: FloatParam::new("Feedback", 0.3, FloatRange::Linear { min: 0.0, max: 0.95 })
+                .with_unit("")
+                .with_value_to_string(Arc::new(|value| format!("{:.2}", value)))
+                .with_step_size(0.001),
+
+            intensity: FloatParam::new("Intensity", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit("")
+                .with_value_to_string(Arc::new(|value| format!("{:.2}", value)))
+                .with_step_size(0.001),
+
+            sync: BoolParam::new("Sync", false),
+
+            division: EnumParam::new("Division", NoteDivision::Eighth),
+
+            stereo_mode: EnumParam::new("Stereo Mode", StereoMode::Independent),
+
+            max_delay_ms: Arc::new(RwLock::new(DEFAULT_MAX_DELAY_MS)),
         }
     }
 }
 
+impl SlapDelay {
+    /// Read a fractional delay tap from `buffer`, linearly interpolating between
+    /// the two neighbouring samples.
+    fn read_tap(buffer: &[f32], write_pos: usize, delay_samples: f32) -> f32 {
+        let len = buffer.len();
+        let i = delay_samples.floor() as usize;
+        let k = delay_samples - delay_samples.floor();
+        let s0 = buffer[(write_pos + len - i) % len];
+        let s1 = buffer[(write_pos + len - i - 1) % len];
+        s0 * (1.0 - k) + s1 * k
+    }
+}
+
 impl Plugin for SlapDelay {
     const NAME: &'static str = "Slap";
     const VENDOR: &'static str = "autoproduccionmusical.com";
@@ -78,10 +193,19 @@ impl Plugin for SlapDelay {
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        // Calcular el máximo tamaño del buffer basado en 1001 ms
-        let max_delay_samples = (buffer_config.sample_rate * 1.001) as usize;
+        // Calcular el máximo tamaño del buffer basado en el máximo delay configurable.
+        // El valor se lee aquí (no en tiempo real) para que pueda fijarse al cargar.
+        // Siempre se cubre al menos el rango del slider (DEFAULT_MAX_DELAY_MS) para
+        // que el tap no se recorte de forma silenciosa. Se reserva una muestra
+        // extra para la interpolación fraccional del tap.
+        let max_delay_ms = self.params.max_delay_ms.read().unwrap().max(DEFAULT_MAX_DELAY_MS);
+        let max_delay_samples = (buffer_config.sample_rate * (max_delay_ms * 0.001)) as usize + 1;
         self.delay_buffer = vec![vec![0.0; max_delay_samples]; 2];
         self.write_pos = 0;
+        // Reset the crossfade so the first block latches its target without a sweep.
+        self.active_delay = -1.0;
+        self.old_delay = -1.0;
+        self.fade = 1.0;
         true
     }
 
@@ -91,27 +215,102 @@ impl Plugin for SlapDelay {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let delay_time_samples = (self.params.delay_time.smoothed.next()
-            * 0.001
-            * context.transport().sample_rate) as usize;
-        // let delay_level = self.params.delay_level.smoothed.next();
+        let sample_rate = context.transport().sample_rate;
         let dry_wet = self.params.dry_wet.value();
+        // Clamp below 1.0 so the feedback path can never run away.
+        let feedback = self.params.feedback.value().min(0.95);
+        let intensity = self.params.intensity.value();
+        let ping_pong = self.params.stereo_mode.value() == StereoMode::PingPong;
+
+        // When sync is on, derive the delay from the host tempo, falling back to
+        // the manual delay time if the host doesn't report a tempo.
+        let synced_samples = if self.params.sync.value() {
+            context.transport().tempo.map(|bpm| {
+                ((60.0 / bpm) * self.params.division.value().quarter_notes() * sample_rate as f64)
+                    as f32
+            })
+        } else {
+            None
+        };
 
         for mut channel_samples in buffer.iter_samples() {
+            // Smooth per-sample so automation sweeps the fractional tap cleanly.
+            let delay_samples = match synced_samples {
+                Some(samples) => {
+                    // Keep the smoother in step with the manual value.
+                    self.params.delay_time.smoothed.next();
+                    samples
+                }
+                None => self.params.delay_time.smoothed.next() * 0.001 * sample_rate,
+            };
+            // A low host tempo can ask for more delay than the buffer holds, so
+            // clamp the read offset to the allocated length before tapping it.
+            let max_offset = (self.delay_buffer[0].len() - 1) as f32;
+            let delay_samples = delay_samples.clamp(0.0, max_offset);
+
+            // Only a musically meaningful jump (~10 ms) triggers a crossfade;
+            // while no fade is in flight, smaller per-sample motion tracks the
+            // smoothed tap directly so a slow sweep isn't mistaken for a retarget.
+            // A retarget that arrives mid-fade abandons the running fade and starts
+            // a new one from the current interpolated length instead of snapping.
+            let retarget_threshold = 0.010 * sample_rate;
+            if self.active_delay < 0.0 {
+                // First sample after (re)allocation: latch without a sweep.
+                self.active_delay = delay_samples;
+                self.old_delay = delay_samples;
+                self.fade = 1.0;
+            } else if (delay_samples - self.active_delay).abs() > retarget_threshold {
+                self.old_delay = self.old_delay * (1.0 - self.fade) + self.active_delay * self.fade;
+                self.active_delay = delay_samples;
+                self.fade = 0.0;
+            } else if self.fade >= 1.0 {
+                // Sub-threshold motion with no fade in flight: follow the tap smoothly.
+                self.active_delay = delay_samples;
+            }
+
+            // Advance the fade over a ~20 ms window.
+            let fade_inc = 1.0 / (0.02 * sample_rate).max(1.0);
+
+            let num_channels = channel_samples.len();
+
+            // Read every channel's crossfaded tap up front so ping-pong mode can
+            // feed the left line from the right line's output and vice versa.
+            let mut delayed = [0.0f32; 2];
+            for channel_idx in 0..num_channels {
+                let buf = &self.delay_buffer[channel_idx];
+                let new_tap = Self::read_tap(buf, self.write_pos, self.active_delay);
+                delayed[channel_idx] = if self.fade < 1.0 {
+                    let old_tap = Self::read_tap(buf, self.write_pos, self.old_delay);
+                    old_tap * (1.0 - self.fade) + new_tap * self.fade
+                } else {
+                    new_tap
+                };
+            }
+
+            // Step the fade once per frame, after both channels have read it.
+            if self.fade < 1.0 {
+                self.fade = (self.fade + fade_inc).min(1.0);
+            }
+
             for (channel_idx, sample) in channel_samples.iter_mut().enumerate() {
-                // Write to delay buffer
-                self.delay_buffer[channel_idx][self.write_pos] = *sample;
+                let delayed_sample = delayed[channel_idx];
 
-                // Calculate read position
-                let read_pos = (self.write_pos + self.delay_buffer[channel_idx].len()
-                    - delay_time_samples)
-                    % self.delay_buffer[channel_idx].len();
+                // In ping-pong mode the regenerated tap crosses over to the other
+                // channel so echoes bounce across the stereo field.
+                let fed_back = if ping_pong && num_channels == 2 {
+                    delayed[1 - channel_idx]
+                } else {
+                    delayed_sample
+                };
 
-                // Read from delay buffer
-                let delayed_sample = self.delay_buffer[channel_idx][read_pos];
+                // Write the input plus the regenerated tap back into the buffer so
+                // the echo repeats instead of slapping only once.
+                self.delay_buffer[channel_idx][self.write_pos] = *sample + feedback * fed_back;
 
-                // Mix dry and wet signals
-                *sample = *sample * (1.0 - dry_wet) + delayed_sample * dry_wet;
+                // Mix dry and wet signals, scaling the echo level by the intensity
+                // so echo loudness can be set apart from feedback; the wet part is
+                // still scaled by dry/wet as well.
+                *sample = *sample * (1.0 - dry_wet) + delayed_sample * intensity * dry_wet;
             }
 
             // Increment and wrap write position